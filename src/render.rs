@@ -4,9 +4,27 @@ use std::io;
 use std::ops::Deref;
 #[cfg(feature = "termcolor")]
 use termcolor::{ColorSpec, WriteColor};
+#[cfg(feature = "unicode-width")]
+use unicode_width::UnicodeWidthStr;
 
 use Doc;
 
+/// Returns the number of display columns `s` occupies.
+///
+/// With the `unicode-width` feature enabled this accounts for East-Asian
+/// wide glyphs (2 columns) and zero-width combining/control characters (0
+/// columns); otherwise it falls back to the byte length, matching the
+/// previous behavior of this module.
+#[cfg(feature = "unicode-width")]
+fn text_width(s: &str) -> usize {
+    UnicodeWidthStr::width(s)
+}
+
+#[cfg(not(feature = "unicode-width"))]
+fn text_width(s: &str) -> usize {
+    s.len()
+}
+
 /// Trait representing the operations necessary to render a document
 pub trait Render {
     type Error;
@@ -20,6 +38,14 @@ pub trait Render {
         }
         Ok(())
     }
+
+    /// Writes `n` ASCII spaces, e.g. for indentation. The default goes
+    /// through `write_str` in fixed-size chunks; backends that buffer their
+    /// own output, such as `BufferedRender`, can override this to append
+    /// the whole run directly instead.
+    fn write_spaces(&mut self, n: usize) -> Result<(), Self::Error> {
+        write_spaces_in_chunks(n, self)
+    }
 }
 
 /// Writes to something implementing `std::io::Write`
@@ -157,137 +183,436 @@ where
     }
 }
 
-#[inline]
-pub fn best<'a, W, T, A>(doc: &'a Doc<'a, T, A>, width: usize, out: &mut W) -> Result<(), W::Error>
+/// A terminal hyperlink annotation, rendered as an OSC 8 escape sequence
+/// (see <https://gist.github.com/egmontkob/eb114294efbcd5adb1944c9f3cb5feda>).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Hyperlink {
+    pub url: String,
+}
+
+impl Hyperlink {
+    pub fn new<S: Into<String>>(url: S) -> Hyperlink {
+        Hyperlink { url: url.into() }
+    }
+}
+
+/// Writes plain text, wrapping `Hyperlink`-annotated spans in OSC 8 escape
+/// sequences so they become clickable in terminals that support it.
+pub struct Hyperlinked<W> {
+    link_stack: Vec<Hyperlink>,
+    upstream: W,
+}
+
+impl<W> Hyperlinked<W> {
+    pub fn new(upstream: W) -> Hyperlinked<W> {
+        Hyperlinked {
+            link_stack: Vec::new(),
+            upstream,
+        }
+    }
+}
+
+impl<W> Render for Hyperlinked<W>
 where
-    T: Deref<Target = Doc<'a, T, A>>,
-    W: ?Sized + RenderAnnotated<A>,
+    W: io::Write,
 {
-    #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
-    enum Mode {
-        Break,
-        Flat,
+    type Error = io::Error;
+
+    fn write_str(&mut self, s: &str) -> io::Result<usize> {
+        self.upstream.write(s.as_bytes())
     }
 
-    type Cmd<'a, T, A> = (usize, Mode, &'a Doc<'a, T, A>);
+    fn write_str_all(&mut self, s: &str) -> io::Result<()> {
+        self.upstream.write_all(s.as_bytes())
+    }
+}
 
-    fn write_newline<W>(ind: usize, out: &mut W) -> Result<(), W::Error>
-    where
-        W: ?Sized + Render,
-    {
-        out.write_str_all("\n")?;
-        write_spaces(ind, out)
+impl<W> RenderAnnotated<Hyperlink> for Hyperlinked<W>
+where
+    W: io::Write,
+{
+    fn push_annotation(&mut self, link: &Hyperlink) -> Result<(), Self::Error> {
+        self.link_stack.push(link.clone());
+        write!(self.upstream, "\x1b]8;;{}\x1b\\", link.url)
     }
 
-    fn write_spaces<W>(spaces: usize, out: &mut W) -> Result<(), W::Error>
-    where
-        W: ?Sized + Render,
-    {
-        macro_rules! make_spaces {
-            () => { "" };
-            ($s: tt $($t: tt)*) => { concat!("          ", make_spaces!($($t)*)) };
+    fn pop_annotation(&mut self) -> Result<(), Self::Error> {
+        self.link_stack.pop();
+        match self.link_stack.last() {
+            Some(previous) => write!(self.upstream, "\x1b]8;;{}\x1b\\", previous.url),
+            None => write!(self.upstream, "\x1b]8;;\x1b\\"),
+        }
+    }
+}
+
+/// Maps a custom annotation type to the SGR (Select Graphic Rendition) bytes
+/// that apply it, e.g. `b"\x1b[1;31m"`. Implement this for your own style
+/// type to drive `AnsiWrite` without depending on `termcolor`.
+pub trait StyleToSgr {
+    fn style_to_sgr(&self) -> Vec<u8>;
+}
+
+/// Writes plain text, applying `A`'s `StyleToSgr` mapping around annotated
+/// spans as raw ANSI SGR escape sequences. Unlike `TermColored` this has no
+/// dependency on `termcolor`, so callers can annotate with any style type
+/// of their own that implements `StyleToSgr`. No `StyleToSgr` impls are
+/// provided for the style types of crates such as `yansi` or `ansi_term`.
+pub struct AnsiWrite<W, A> {
+    style_stack: Vec<A>,
+    upstream: W,
+}
+
+impl<W, A> AnsiWrite<W, A> {
+    pub fn new(upstream: W) -> AnsiWrite<W, A> {
+        AnsiWrite {
+            style_stack: Vec::new(),
+            upstream,
         }
+    }
+}
+
+impl<W, A> Render for AnsiWrite<W, A>
+where
+    W: io::Write,
+{
+    type Error = io::Error;
 
-        const SPACES: &str = make_spaces!(,,,,,,,,,,);
-        let mut inserted = 0;
-        while inserted < spaces {
-            let insert = cmp::min(SPACES.len(), spaces - inserted);
-            inserted += out.write_str(&SPACES[..insert])?;
+    fn write_str(&mut self, s: &str) -> io::Result<usize> {
+        self.upstream.write(s.as_bytes())
+    }
+
+    fn write_str_all(&mut self, s: &str) -> io::Result<()> {
+        self.upstream.write_all(s.as_bytes())
+    }
+}
+
+impl<W, A> RenderAnnotated<A> for AnsiWrite<W, A>
+where
+    W: io::Write,
+    A: Clone + StyleToSgr,
+{
+    fn push_annotation(&mut self, style: &A) -> Result<(), Self::Error> {
+        self.style_stack.push(style.clone());
+        self.upstream.write_all(&style.style_to_sgr())
+    }
+
+    fn pop_annotation(&mut self) -> Result<(), Self::Error> {
+        self.style_stack.pop();
+        match self.style_stack.last() {
+            Some(previous) => self.upstream.write_all(&previous.style_to_sgr()),
+            None => self.upstream.write_all(b"\x1b[0m"),
         }
+    }
+}
 
+/// A byte-offset span into `SpanRenderer`'s plain-text output, carrying the
+/// annotation that covered it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Span<A> {
+    pub start: usize,
+    pub end: usize,
+    pub annotation: A,
+}
+
+/// Renders into a plain-text buffer plus a flat list of byte-offset spans,
+/// one per annotation, instead of emitting escape codes. Downstream code
+/// can turn the result into HTML `<span>`s, JSON, or an annotate-snippet
+/// style view.
+pub struct SpanRenderer<A> {
+    buffer: String,
+    spans: Vec<Span<A>>,
+    open: Vec<(usize, A)>,
+}
+
+impl<A> SpanRenderer<A> {
+    pub fn new() -> SpanRenderer<A> {
+        SpanRenderer {
+            buffer: String::new(),
+            spans: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+
+    /// Consumes the renderer, returning the rendered plain text and its
+    /// spans, ordered by start offset and then by decreasing length so
+    /// consumers can reconstruct the (possibly nested) annotation tree.
+    pub fn finish(mut self) -> (String, Vec<Span<A>>) {
+        self.spans
+            .sort_by_key(|span| (span.start, cmp::Reverse(span.end - span.start)));
+        (self.buffer, self.spans)
+    }
+}
+
+impl<A> Default for SpanRenderer<A> {
+    fn default() -> Self {
+        SpanRenderer::new()
+    }
+}
+
+impl<A> Render for SpanRenderer<A> {
+    type Error = fmt::Error;
+
+    fn write_str(&mut self, s: &str) -> Result<usize, Self::Error> {
+        self.buffer.push_str(s);
+        Ok(s.len())
+    }
+
+    fn write_str_all(&mut self, s: &str) -> Result<(), Self::Error> {
+        self.buffer.push_str(s);
+        Ok(())
+    }
+}
+
+impl<A> RenderAnnotated<A> for SpanRenderer<A>
+where
+    A: Clone,
+{
+    fn push_annotation(&mut self, annotation: &A) -> Result<(), Self::Error> {
+        self.open.push((self.buffer.len(), annotation.clone()));
+        Ok(())
+    }
+
+    fn pop_annotation(&mut self) -> Result<(), Self::Error> {
+        if let Some((start, annotation)) = self.open.pop() {
+            self.spans.push(Span {
+                start,
+                end: self.buffer.len(),
+                annotation,
+            });
+        }
         Ok(())
     }
+}
+
+/// Wraps another `Render` backend, batching writes into an internal buffer
+/// and flushing them to `upstream` in large blocks instead of issuing one
+/// write per `Doc::Text` or space run. Useful when `upstream` is backed by
+/// an unbuffered `io::Write`, such as a raw socket or a stdout lock.
+pub struct BufferedRender<W> {
+    buffer: Vec<u8>,
+    upstream: W,
+}
+
+impl<W> BufferedRender<W> {
+    pub fn new(upstream: W) -> BufferedRender<W> {
+        BufferedRender {
+            buffer: Vec::new(),
+            upstream,
+        }
+    }
+}
+
+impl<W> BufferedRender<W>
+where
+    W: Render,
+{
+    /// Drains the internal buffer into `upstream` with a single write.
+    pub fn flush(&mut self) -> Result<(), W::Error> {
+        if !self.buffer.is_empty() {
+            // `self.buffer` only ever receives bytes of `&str`s and ASCII
+            // spaces, so it is always valid UTF-8.
+            let s = str::from_utf8(&self.buffer).expect("buffer contains valid UTF-8");
+            self.upstream.write_str_all(s)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Flushes any remaining buffered output and returns the upstream writer.
+    pub fn finish(mut self) -> Result<W, W::Error> {
+        self.flush()?;
+        Ok(self.upstream)
+    }
+}
+
+impl<W> Render for BufferedRender<W>
+where
+    W: Render,
+{
+    type Error = W::Error;
+
+    fn write_str(&mut self, s: &str) -> Result<usize, Self::Error> {
+        self.buffer.extend_from_slice(s.as_bytes());
+        Ok(s.len())
+    }
+
+    fn write_str_all(&mut self, s: &str) -> Result<(), Self::Error> {
+        self.buffer.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    // Fast path for the common case of writing indentation: fill the run
+    // of spaces directly with one bulk append instead of going through
+    // `write_spaces_in_chunks`'s repeated fixed-size `write_str` calls.
+    fn write_spaces(&mut self, n: usize) -> Result<(), Self::Error> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + n, b' ');
+        Ok(())
+    }
+}
+
+impl<A, W> RenderAnnotated<A> for BufferedRender<W>
+where
+    W: RenderAnnotated<A>,
+{
+    // Flush before every push/pop so buffered text always lands before the
+    // escape sequence that follows it, keeping output order correct.
+    fn push_annotation(&mut self, annotation: &A) -> Result<(), Self::Error> {
+        self.flush()?;
+        self.upstream.push_annotation(annotation)
+    }
+
+    fn pop_annotation(&mut self) -> Result<(), Self::Error> {
+        self.flush()?;
+        self.upstream.pop_annotation()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Mode {
+    Break,
+    Flat,
+}
+
+type Cmd<'a, T, A> = (usize, Mode, &'a Doc<'a, T, A>);
+
+fn write_newline<W>(ind: usize, out: &mut W) -> Result<(), W::Error>
+where
+    W: ?Sized + Render,
+{
+    out.write_str_all("\n")?;
+    out.write_spaces(ind)
+}
+
+fn write_spaces_in_chunks<W>(spaces: usize, out: &mut W) -> Result<(), W::Error>
+where
+    W: ?Sized + Render,
+{
+    macro_rules! make_spaces {
+        () => { "" };
+        ($s: tt $($t: tt)*) => { concat!("          ", make_spaces!($($t)*)) };
+    }
+
+    const SPACES: &str = make_spaces!(,,,,,,,,,,);
+    let mut inserted = 0;
+    while inserted < spaces {
+        let insert = cmp::min(SPACES.len(), spaces - inserted);
+        inserted += out.write_str(&SPACES[..insert])?;
+    }
+
+    Ok(())
+}
+
+fn fitting<'a, T, A>(
+    next: &'a Doc<'a, T, A>,
+    bcmds: &[Cmd<'a, T, A>],
+    fcmds: &mut Vec<&'a Doc<'a, T, A>>,
+    mut rem: isize,
+    newline_fits: fn(Mode) -> bool,
+) -> bool
+where
+    T: Deref<Target = Doc<'a, T, A>>,
+{
+    let mut bidx = bcmds.len();
+    fcmds.clear(); // clear from previous calls from best
+    fcmds.push(next);
+
+    let mut mode = Mode::Flat;
+    loop {
+        let mut doc = match fcmds.pop() {
+            None => {
+                if bidx == 0 {
+                    // All commands have been processed
+                    return true;
+                } else {
+                    bidx -= 1;
+                    mode = Mode::Break;
+                    bcmds[bidx].2
+                }
+            }
+            Some(cmd) => cmd,
+        };
 
-    fn fitting<'a, T, A>(
-        next: &'a Doc<'a, T, A>,
-        bcmds: &[Cmd<'a, T, A>],
-        fcmds: &mut Vec<&'a Doc<'a, T, A>>,
-        mut rem: isize,
-        newline_fits: fn(Mode) -> bool,
-    ) -> bool
-    where
-        T: Deref<Target = Doc<'a, T, A>>,
-    {
-        let mut bidx = bcmds.len();
-        fcmds.clear(); // clear from previous calls from best
-        fcmds.push(next);
-
-        let mut mode = Mode::Flat;
         loop {
-            let mut doc = match fcmds.pop() {
-                None => {
-                    if bidx == 0 {
-                        // All commands have been processed
-                        return true;
-                    } else {
-                        bidx -= 1;
-                        mode = Mode::Break;
-                        bcmds[bidx].2
+            match *doc {
+                Doc::Nil => {}
+                Doc::Append(ref ldoc, ref rdoc) => {
+                    fcmds.push(rdoc);
+                    // Since appended documents often appear in sequence on the left side we
+                    // gain a slight performance increase by batching these pushes (avoiding
+                    // to push and directly pop `Append` documents)
+                    doc = ldoc;
+                    while let Doc::Append(ref l, ref r) = *doc {
+                        fcmds.push(r);
+                        doc = l;
                     }
+                    continue;
                 }
-                Some(cmd) => cmd,
-            };
-
-            loop {
-                match *doc {
-                    Doc::Nil => {}
-                    Doc::Append(ref ldoc, ref rdoc) => {
-                        fcmds.push(rdoc);
-                        // Since appended documents often appear in sequence on the left side we
-                        // gain a slight performance increase by batching these pushes (avoiding
-                        // to push and directly pop `Append` documents)
-                        doc = ldoc;
-                        while let Doc::Append(ref l, ref r) = *doc {
-                            fcmds.push(r);
-                            doc = l;
-                        }
-                        continue;
-                    }
-                    Doc::Space => match mode {
-                        Mode::Flat => {
-                            rem -= 1;
-                            if rem < 0 {
-                                return false;
-                            }
-                        }
-                        Mode::Break => return true,
-                    },
-                    // Newlines inside the group makes it not fit, but those outside lets it
-                    // fit on the current line
-                    Doc::Newline => return newline_fits(mode),
-                    Doc::Text(ref str) => {
-                        rem -= str.len() as isize;
+                Doc::Space => match mode {
+                    Mode::Flat => {
+                        rem -= 1;
                         if rem < 0 {
                             return false;
                         }
                     }
-                    Doc::FlatAlt(ref b, ref f) => {
-                        doc = match mode {
-                            Mode::Break => b,
-                            Mode::Flat => f,
-                        };
-                        continue;
+                    Mode::Break => return true,
+                },
+                // Newlines inside the group makes it not fit, but those outside lets it
+                // fit on the current line
+                Doc::Newline => return newline_fits(mode),
+                Doc::Text(ref str) => {
+                    rem -= text_width(str) as isize;
+                    if rem < 0 {
+                        return false;
                     }
+                }
+                Doc::FlatAlt(ref b, ref f) => {
+                    doc = match mode {
+                        Mode::Break => b,
+                        Mode::Flat => f,
+                    };
+                    continue;
+                }
 
-                    Doc::Nest(_, ref next)
-                    | Doc::Group(ref next)
-                    | Doc::Annotated(_, ref next)
-                    | Doc::Union(_, ref next) => {
-                        doc = next;
-                        continue;
-                    }
+                Doc::Nest(_, ref next)
+                | Doc::Group(ref next)
+                | Doc::Annotated(_, ref next)
+                | Doc::Union(_, ref next) => {
+                    doc = next;
+                    continue;
                 }
-                break;
             }
+            break;
         }
     }
+}
 
+/// The shared document walk backing both `best` and `best_bounded_with`.
+///
+/// When `budget` is `None` the whole document is rendered with no line
+/// limit. When it is `Some((max_lines, marker))`, the walk stops at the
+/// first line break beyond the first `max_lines` breaks, appends a line
+/// containing `marker` in place of the remainder, and pops any
+/// annotations that were still open so push/pop stays balanced even on
+/// early termination.
+fn render<'a, W, T, A>(
+    doc: &'a Doc<'a, T, A>,
+    width: usize,
+    budget: Option<(usize, &str)>,
+    out: &mut W,
+) -> Result<(), W::Error>
+where
+    T: Deref<Target = Doc<'a, T, A>>,
+    W: ?Sized + RenderAnnotated<A>,
+{
     let mut pos = 0;
+    let mut line = 0;
     let mut bcmds = vec![(0, Mode::Break, doc)];
     let mut fcmds = vec![];
     let mut annotation_levels = vec![];
+    let mut truncated = false;
 
-    while let Some(mut cmd) = bcmds.pop() {
+    'outer: while let Some(mut cmd) = bcmds.pop() {
         loop {
             let (ind, mode, doc) = cmd;
             match *doc {
@@ -334,20 +659,34 @@ where
                 }
                 Doc::Space => match mode {
                     Mode::Flat => {
-                        write_spaces(1, out)?;
+                        out.write_spaces(1)?;
                     }
                     Mode::Break => {
+                        if let Some((max_lines, _)) = budget {
+                            if line >= max_lines {
+                                truncated = true;
+                                break 'outer;
+                            }
+                        }
                         write_newline(ind, out)?;
                         pos = ind;
+                        line += 1;
                     }
                 },
                 Doc::Newline => {
+                    if let Some((max_lines, _)) = budget {
+                        if line >= max_lines {
+                            truncated = true;
+                            break 'outer;
+                        }
+                    }
                     write_newline(ind, out)?;
                     pos = ind;
+                    line += 1;
                 }
                 Doc::Text(ref s) => {
                     out.write_str_all(s)?;
-                    pos += s.len();
+                    pos += text_width(s);
                 }
                 Doc::Annotated(ref ann, ref doc) => {
                     out.push_annotation(ann)?;
@@ -368,7 +707,19 @@ where
 
             break;
         }
-        if annotation_levels.last() == Some(&bcmds.len()) {
+        while annotation_levels.last() == Some(&bcmds.len()) {
+            annotation_levels.pop();
+            out.pop_annotation()?;
+        }
+    }
+
+    if truncated {
+        // `budget` is always `Some` here: `truncated` is only set inside the
+        // `if let Some(...)` branches above.
+        let (_, marker) = budget.unwrap();
+        out.write_str_all("\n")?;
+        out.write_str_all(marker)?;
+        while !annotation_levels.is_empty() {
             annotation_levels.pop();
             out.pop_annotation()?;
         }
@@ -376,3 +727,304 @@ where
 
     Ok(())
 }
+
+#[inline]
+pub fn best<'a, W, T, A>(doc: &'a Doc<'a, T, A>, width: usize, out: &mut W) -> Result<(), W::Error>
+where
+    T: Deref<Target = Doc<'a, T, A>>,
+    W: ?Sized + RenderAnnotated<A>,
+{
+    render(doc, width, None, out)
+}
+
+/// The truncation marker `best_bounded` emits by default when its line
+/// budget runs out.
+pub const DEFAULT_TRUNCATION_MARKER: &str = "…";
+
+/// Like `best`, but emits at most `max_lines` lines: when another line
+/// break is due after `max_lines - 1` lines, it bails out and emits
+/// `DEFAULT_TRUNCATION_MARKER` as the last line in place of the remainder
+/// (see `best_bounded_with` for the exact accounting). Useful
+/// for rendering into a fixed-height terminal region, such as a list capped
+/// to its visible rows.
+#[inline]
+pub fn best_bounded<'a, W, T, A>(
+    doc: &'a Doc<'a, T, A>,
+    width: usize,
+    max_lines: usize,
+    out: &mut W,
+) -> Result<(), W::Error>
+where
+    T: Deref<Target = Doc<'a, T, A>>,
+    W: ?Sized + RenderAnnotated<A>,
+{
+    best_bounded_with(doc, width, max_lines, 0, DEFAULT_TRUNCATION_MARKER, out)
+}
+
+/// Like `best_bounded`, but stops `scroll_padding` lines before `max_lines`
+/// and uses `marker` as the truncation line instead of the default `…`.
+///
+/// `max_lines` bounds the total number of lines emitted, including both the
+/// implicit first line (written before any line break) and, if the budget
+/// is exhausted, the marker line itself — so the line counter only has
+/// `max_lines - scroll_padding - 2` breaks to spend before truncating. If
+/// that leaves no room for a content line alongside the marker (i.e.
+/// `max_lines - scroll_padding <= 1`), `marker` is emitted on its own
+/// instead of silently exceeding `max_lines`. Otherwise, if the budget is
+/// exhausted before the document is fully rendered, a single line
+/// containing `marker` is emitted and any annotations still open at that
+/// point are popped so push/pop stays balanced even on early termination.
+pub fn best_bounded_with<'a, W, T, A>(
+    doc: &'a Doc<'a, T, A>,
+    width: usize,
+    max_lines: usize,
+    scroll_padding: usize,
+    marker: &str,
+    out: &mut W,
+) -> Result<(), W::Error>
+where
+    T: Deref<Target = Doc<'a, T, A>>,
+    W: ?Sized + RenderAnnotated<A>,
+{
+    let effective_max = max_lines.saturating_sub(scroll_padding);
+    if effective_max <= 1 {
+        return out.write_str_all(marker);
+    }
+    render(doc, width, Some((effective_max - 2, marker)), out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    struct TestDoc<'a>(Box<Doc<'a, TestDoc<'a>, ()>>);
+
+    impl<'a> Deref for TestDoc<'a> {
+        type Target = Doc<'a, TestDoc<'a>, ()>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    fn text<'a>(s: &str) -> TestDoc<'a> {
+        TestDoc(Box::new(Doc::Text(Cow::Owned(s.to_owned()))))
+    }
+
+    fn append<'a>(l: TestDoc<'a>, r: TestDoc<'a>) -> TestDoc<'a> {
+        TestDoc(Box::new(Doc::Append(l, r)))
+    }
+
+    fn newline<'a>() -> TestDoc<'a> {
+        TestDoc(Box::new(Doc::Newline))
+    }
+
+    // A document of `extra_lines + 1` lines: "line", then `extra_lines`
+    // more, each preceded by a newline.
+    fn lines<'a>(extra_lines: usize) -> TestDoc<'a> {
+        let mut doc = text("line");
+        for _ in 0..extra_lines {
+            doc = append(append(doc, newline()), text("line"));
+        }
+        doc
+    }
+
+    #[test]
+    fn text_width_matches_byte_len_for_ascii() {
+        // Regardless of whether the `unicode-width` feature is enabled,
+        // plain ASCII text occupies one column per byte.
+        assert_eq!(text_width("hello, world"), "hello, world".len());
+        assert_eq!(text_width(""), 0);
+    }
+
+    #[test]
+    fn hyperlinked_restores_enclosing_link_on_pop() {
+        let mut out = Hyperlinked::new(Vec::new());
+        out.push_annotation(&Hyperlink::new("outer")).unwrap();
+        out.push_annotation(&Hyperlink::new("inner")).unwrap();
+        out.pop_annotation().unwrap();
+        out.pop_annotation().unwrap();
+
+        let written = String::from_utf8(out.upstream).unwrap();
+        assert_eq!(
+            written,
+            "\x1b]8;;outer\x1b\\\x1b]8;;inner\x1b\\\x1b]8;;outer\x1b\\\x1b]8;;\x1b\\"
+        );
+    }
+
+    #[derive(Clone)]
+    struct TestStyle(u8);
+
+    impl StyleToSgr for TestStyle {
+        fn style_to_sgr(&self) -> Vec<u8> {
+            format!("\x1b[{}m", self.0).into_bytes()
+        }
+    }
+
+    #[test]
+    fn ansi_write_restores_enclosing_style_on_pop() {
+        let mut out: AnsiWrite<Vec<u8>, TestStyle> = AnsiWrite::new(Vec::new());
+        out.push_annotation(&TestStyle(31)).unwrap();
+        out.push_annotation(&TestStyle(1)).unwrap();
+        out.pop_annotation().unwrap();
+        out.pop_annotation().unwrap();
+
+        let written = String::from_utf8(out.upstream).unwrap();
+        assert_eq!(written, "\x1b[31m\x1b[1m\x1b[31m\x1b[0m");
+    }
+
+    #[test]
+    fn span_renderer_captures_nested_annotations() {
+        let mut out = SpanRenderer::new();
+        out.write_str_all("a").unwrap();
+        out.push_annotation(&"outer").unwrap();
+        out.write_str_all("b").unwrap();
+        out.push_annotation(&"middle").unwrap();
+        out.write_str_all("c").unwrap();
+        out.push_annotation(&"inner").unwrap();
+        out.write_str_all("d").unwrap();
+        out.pop_annotation().unwrap();
+        out.pop_annotation().unwrap();
+        out.pop_annotation().unwrap();
+        out.write_str_all("e").unwrap();
+
+        let (text, spans) = out.finish();
+        assert_eq!(text, "abcde");
+        // Ordered by start, then by decreasing length, so the outermost
+        // span at each start offset comes first.
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    start: 1,
+                    end: 4,
+                    annotation: "outer"
+                },
+                Span {
+                    start: 2,
+                    end: 4,
+                    annotation: "middle"
+                },
+                Span {
+                    start: 3,
+                    end: 4,
+                    annotation: "inner"
+                },
+            ]
+        );
+    }
+
+    struct AnnotatedTestDoc<'a>(Box<Doc<'a, AnnotatedTestDoc<'a>, &'static str>>);
+
+    impl<'a> Deref for AnnotatedTestDoc<'a> {
+        type Target = Doc<'a, AnnotatedTestDoc<'a>, &'static str>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn buffered_render_interleaves_text_and_spaces_correctly() {
+        let mut rendered = String::new();
+        {
+            let mut out = BufferedRender::new(FmtWrite::new(&mut rendered));
+            out.write_str_all("foo").unwrap();
+            out.write_spaces(3).unwrap();
+            out.write_str_all("bar").unwrap();
+            out.finish().unwrap();
+        }
+        assert_eq!(rendered, "foo   bar");
+    }
+
+    #[test]
+    fn best_closes_all_annotations_ending_at_the_same_depth() {
+        // Annotated("outer", Annotated("inner", Text("x"))): both
+        // annotations close at the same point in the walk, which is
+        // exactly the case that requires popping in a `while` loop
+        // instead of a single `if`.
+        let doc = AnnotatedTestDoc(Box::new(Doc::Annotated(
+            "outer",
+            AnnotatedTestDoc(Box::new(Doc::Annotated(
+                "inner",
+                AnnotatedTestDoc(Box::new(Doc::Text(Cow::Borrowed("x")))),
+            ))),
+        )));
+
+        let mut out = SpanRenderer::new();
+        best(&doc, 80, &mut out).unwrap();
+        let (text, spans) = out.finish();
+
+        assert_eq!(text, "x");
+        // Both spans tie on (start, length), so the sort is stable and
+        // preserves push/pop order: the inner annotation is popped (and
+        // thus pushed into `spans`) first.
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    start: 0,
+                    end: 1,
+                    annotation: "inner"
+                },
+                Span {
+                    start: 0,
+                    end: 1,
+                    annotation: "outer"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn best_bounded_never_exceeds_max_lines() {
+        for max_lines in 0..8 {
+            let doc = lines(9);
+            let mut rendered = String::new();
+            best_bounded(&doc, 80, max_lines, &mut FmtWrite::new(&mut rendered)).unwrap();
+
+            // A budget of 0 or 1 lines still has to emit the marker by
+            // itself (see `best_bounded_falls_back_to_marker_for_tiny_budgets`),
+            // so the achievable floor is 1 line, not `max_lines`.
+            let line_count = rendered.matches('\n').count() + 1;
+            let limit = cmp::max(max_lines, 1);
+            assert!(
+                line_count <= limit,
+                "max_lines={}: rendered {} lines: {:?}",
+                max_lines,
+                line_count,
+                rendered
+            );
+        }
+    }
+
+    #[test]
+    fn best_bounded_falls_back_to_marker_for_tiny_budgets() {
+        let doc = lines(9);
+        for max_lines in 0..=1 {
+            let mut rendered = String::new();
+            best_bounded(&doc, 80, max_lines, &mut FmtWrite::new(&mut rendered)).unwrap();
+            assert_eq!(rendered, DEFAULT_TRUNCATION_MARKER);
+        }
+    }
+
+    #[test]
+    fn best_bounded_marks_truncation() {
+        let doc = lines(9);
+        let mut rendered = String::new();
+        best_bounded(&doc, 80, 5, &mut FmtWrite::new(&mut rendered)).unwrap();
+
+        assert!(rendered.ends_with(DEFAULT_TRUNCATION_MARKER));
+    }
+
+    #[test]
+    fn best_bounded_does_not_truncate_when_under_budget() {
+        let doc = lines(2);
+        let mut rendered = String::new();
+        best_bounded(&doc, 80, 80, &mut FmtWrite::new(&mut rendered)).unwrap();
+
+        assert!(!rendered.contains(DEFAULT_TRUNCATION_MARKER));
+        assert_eq!(rendered, "line\nline\nline");
+    }
+}